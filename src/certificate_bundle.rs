@@ -0,0 +1,39 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2016 Marvin Böcker
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use edcert::certificate::Certificate;
+
+/// A sequence of certificates - typically a leaf certificate together with the intermediate
+/// certificates that signed it - that can be saved and loaded as a single unit. This is what a
+/// verifier walking `edcert`'s parent links needs in order to reconstruct a full chain from one
+/// file, instead of having to fetch each intermediate separately.
+pub struct CertificateBundle {
+    /// The certificates in this bundle, in the order they were added.
+    pub certificates: Vec<Certificate>,
+}
+
+impl CertificateBundle {
+    /// Creates a bundle from an existing list of certificates.
+    pub fn new(certificates: Vec<Certificate>) -> CertificateBundle {
+        CertificateBundle { certificates: certificates }
+    }
+}
@@ -20,16 +20,136 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 // SOFTWARE.
 
+use codec::Codec;
 use edcert;
 use edcert::certificate::Certificate;
+use error::CertificateError;
 use lzma;
+use serialization::Serialization;
+use std::io::{Read, Write};
 
-static CERTIFICATE_COMPRESSOR_FORMAT_VERSION: [u8; 3] = [1, 1, 0];
+/// Bumped from 1.1.0 to 1.2.0 when the codec byte was introduced and again to 1.3.0 when the
+/// serialization-format byte was introduced; all three remain within the `^1.0.0` range
+/// `decode` accepts, but 1.2.0 only has a codec byte and 1.3.0 also has a format byte.
+static CERTIFICATE_COMPRESSOR_FORMAT_VERSION: [u8; 3] = [1, 3, 0];
+
+/// The version written into the header of files produced by `encode_encrypted`. Its major
+/// component (3) is intentionally outside the `^1.0.0` range `decode` accepts, so plaintext
+/// `decode` correctly refuses an encrypted file instead of trying to LZMA-decompress ciphertext.
+/// Bumped from 3.0.0 to 3.1.0 when the Argon2id parameters (`ARGON2_PARAMS_LEN` bytes, right
+/// after the mode byte) were added to the header: 3.0.x files carry no such bytes and are
+/// decrypted with `argon2::Config::default()`, the only parameters that version ever used.
+static CERTIFICATE_COMPRESSOR_ENCRYPTED_FORMAT_VERSION: [u8; 3] = [3, 1, 0];
+
+/// Argon2id + XChaCha20-Poly1305, the only encryption mode implemented so far.
+const MODE_ARGON2ID_XCHACHA20POLY1305: u8 = 1;
+
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+const KEY_LEN: usize = 32;
+
+/// The Argon2id parameters this crate derives new encryption keys with. Recorded in the header
+/// of every file written by `encrypt_payload` (see `CERTIFICATE_COMPRESSOR_ENCRYPTED_FORMAT_VERSION`)
+/// rather than left implicit, so that a later change to this constant - or to the `argon2`
+/// crate's own defaults, which used to be relied on directly - can never make an
+/// already-encrypted file silently undecryptable.
+const ARGON2_M_COST: u32 = 65_536;
+const ARGON2_T_COST: u32 = 3;
+const ARGON2_PARALLELISM: u32 = 1;
+
+/// `m_cost(4) || t_cost(4) || parallelism(4)`, all big-endian, as written into the header by
+/// `encrypt_payload`.
+const ARGON2_PARAMS_LEN: usize = 12;
+
+/// Distinguishes a `CertificateBundle` file from a single-certificate file up front, so
+/// `decode_bundle` never mistakes one for the other.
+static CERTIFICATE_BUNDLE_MAGIC: &'static [u8; 3] = b"edb";
+static CERTIFICATE_BUNDLE_FORMAT_VERSION: [u8; 3] = [1, 0, 0];
+
+/// The default decompressed-size budget `decode` enforces. Generous for any real certificate,
+/// but small enough that a crafted file a few bytes long cannot make LZMA or zstd allocate
+/// gigabytes on this process's behalf.
+const DEFAULT_MAX_DECOMPRESSED_BYTES: usize = 1024 * 1024;
+
+/// The cap `decode_from_reader` enforces on bytes read from the stream itself, as opposed to
+/// `DEFAULT_MAX_DECOMPRESSED_BYTES` which only bounds the decompressed payload. Far more generous
+/// than any real encoded certificate needs, but finite, so a hostile `Read` that never yields EOF
+/// cannot force an unbounded allocation before decoding even starts.
+const DEFAULT_MAX_ENCODED_BYTES: usize = 16 * 1024 * 1024;
+
+fn u32_to_be_bytes(n: u32) -> [u8; 4] {
+    [(n >> 24) as u8, (n >> 16) as u8, (n >> 8) as u8, n as u8]
+}
+
+fn be_bytes_to_u32(bytes: &[u8]) -> u32 {
+    ((bytes[0] as u32) << 24) | ((bytes[1] as u32) << 16) | ((bytes[2] as u32) << 8) |
+    (bytes[3] as u32)
+}
+
+fn argon2_params_to_bytes(m_cost: u32, t_cost: u32, parallelism: u32) -> [u8; ARGON2_PARAMS_LEN] {
+    let mut out = [0u8; ARGON2_PARAMS_LEN];
+    out[0..4].copy_from_slice(&u32_to_be_bytes(m_cost));
+    out[4..8].copy_from_slice(&u32_to_be_bytes(t_cost));
+    out[8..12].copy_from_slice(&u32_to_be_bytes(parallelism));
+    out
+}
 
 /// This type can be used to save `Certificate`s.
 pub struct CertificateCompressor;
 
 impl CertificateCompressor {
+    /// LZMA-compresses the JSON encoding of `cert`. The returned buffer is a complete XZ stream,
+    /// LZMA's own magic bytes included; `encode_encrypted` encrypts it whole.
+    fn compress(cert: &Certificate) -> Result<Vec<u8>, CertificateError> {
+        use rustc_serialize::json;
+
+        let jsoncode = try!(json::encode(cert).map_err(|e| CertificateError::Json(e.to_string())));
+        lzma::compress(jsoncode.as_bytes(), 6).map_err(|_| CertificateError::Decompress)
+    }
+
+    /// Reverses `compress`: `bytes` must still carry the real LZMA magic at its front. Bounded by
+    /// `max_bytes`, for the same reason `decode_with_limit` is.
+    fn decompress_with_limit(bytes: &[u8], max_bytes: usize) -> Result<Certificate, CertificateError> {
+        use rustc_serialize::json;
+
+        let decompressed = try!(Codec::Lzma.decompress_with_limit(bytes, max_bytes));
+        let decoded = try!(String::from_utf8(decompressed).map_err(|_| CertificateError::Utf8));
+        json::decode(&decoded).map_err(|e| CertificateError::Json(e.to_string()))
+    }
+
+    /// Derives a 32-byte key from `passphrase` and `salt` using Argon2id with the given
+    /// `m_cost`/`t_cost`/`parallelism`. `encrypt_payload` always passes `ARGON2_M_COST` /
+    /// `ARGON2_T_COST` / `ARGON2_PARALLELISM` and records them in the header; `decrypt_payload`
+    /// reads them back from there instead of assuming they match whatever this crate currently
+    /// uses.
+    fn derive_key(passphrase: &str,
+                   salt: &[u8],
+                   m_cost: u32,
+                   t_cost: u32,
+                   parallelism: u32)
+                   -> Result<Vec<u8>, CertificateError> {
+        let mut config = argon2::Config::default();
+        config.variant = argon2::Variant::Argon2id;
+        config.hash_length = KEY_LEN as u32;
+        config.mem_cost = m_cost;
+        config.time_cost = t_cost;
+        config.lanes = parallelism;
+        argon2::hash_raw(passphrase.as_bytes(), salt, &config)
+            .map_err(|_| CertificateError::KeyDerivation)
+    }
+
+    /// Derives a key the way every file written before `CERTIFICATE_COMPRESSOR_ENCRYPTED_FORMAT_VERSION`
+    /// 3.1.0 did: whatever `argon2::Config::default()`'s own `mem_cost`/`time_cost`/`lanes` happen
+    /// to be, since those files recorded no parameters of their own. Kept only so they stay
+    /// decryptable; `encrypt_payload` never uses this.
+    fn derive_key_legacy(passphrase: &str, salt: &[u8]) -> Result<Vec<u8>, CertificateError> {
+        let mut config = argon2::Config::default();
+        config.variant = argon2::Variant::Argon2id;
+        config.hash_length = KEY_LEN as u32;
+        argon2::hash_raw(passphrase.as_bytes(), salt, &config)
+            .map_err(|_| CertificateError::KeyDerivation)
+    }
+
     fn get_version_from_bytes(bytes: &[u8]) -> String {
         if bytes == b"ert" {
             "1.0.0".to_string()
@@ -45,13 +165,17 @@ impl CertificateCompressor {
         CERTIFICATE_COMPRESSOR_FORMAT_VERSION
     }
 
-    /// takes a json-encoded byte vector and tries to create a certificate from it.
-    pub fn decode(compressed: &[u8]) -> Result<Certificate, &'static str> {
-        use rustc_serialize::json;
-
+    /// Like `decode`, but lets the caller pick the decompressed-size budget instead of the
+    /// crate's default. Returns `CertificateError::TooLarge` if the payload would decompress to
+    /// more than `max_bytes`, instead of letting a crafted file exhaust memory.
+    pub fn decode_with_limit(compressed: &[u8], max_bytes: usize) -> Result<Certificate, CertificateError> {
         use semver::Version;
         use semver::VersionReq;
 
+        if compressed.len() < 6 {
+            return Err(CertificateError::Truncated);
+        }
+
         // create a byte vector
         let mut bytes: Vec<u8> = Vec::new();
 
@@ -59,50 +183,354 @@ impl CertificateCompressor {
         bytes.extend_from_slice(compressed);
 
         // read version from the file format
-        let version = CertificateCompressor::get_version_from_bytes(&bytes[3..6]);
-        let version = Version::parse(&version).expect("Failed to parse file format version");
+        let version_string = CertificateCompressor::get_version_from_bytes(&bytes[3..6]);
+        let version = match Version::parse(&version_string) {
+            Ok(v) => v,
+            Err(_) => {
+                return Err(CertificateError::IncompatibleVersion {
+                    found: version_string,
+                    required: "^1.0.0".to_string(),
+                })
+            }
+        };
         let vreq = VersionReq::parse("^1.0.0").expect("Failed to parse version requirement.");
 
-        if vreq.matches(&version) {
-            // overwrite with LZMA magic bytes
+        if !vreq.matches(&version) {
+            return Err(CertificateError::IncompatibleVersion {
+                found: version_string,
+                required: "^1.0.0".to_string(),
+            });
+        }
+
+        if version.minor < 2 {
+            use rustc_serialize::json;
+
+            // legacy layout: no codec byte, LZMA's own magic was overwritten by the header and
+            // must be restored before decompressing.
             let magic: [u8; 6] = [0xfd, 0x37, 0x7a, 0x58, 0x5a, 0x00];
             edcert::copy_bytes(&mut bytes[0..6], &magic, 0, 0, 6);
 
-            // decompress the vector
-            let o = lzma::decompress(&bytes[..]);
-            if o.is_err() {
-                return Err("Failed to decompress certificate");
+            let decompressed = try!(Codec::Lzma.decompress_with_limit(&bytes[..], max_bytes));
+            let decoded = try!(String::from_utf8(decompressed).map_err(|_| CertificateError::Utf8));
+            json::decode(&decoded).map_err(|e| CertificateError::Json(e.to_string()))
+        } else {
+            if bytes.len() < 7 {
+                return Err(CertificateError::Truncated);
             }
 
-            // read utf8 string
-            let o = String::from_utf8(o.unwrap());
-            if o.is_err() {
-                return Err("Failed to read UTF8 from decompressed vector");
-            }
+            let codec = match Codec::from_byte(bytes[6]) {
+                Some(c) => c,
+                None => return Err(CertificateError::UnsupportedMode(bytes[6])),
+            };
 
-            // decode json object and return Certificate
-            let o = json::decode(&o.unwrap());
-            if o.is_err() {
-                Err("Failed to decode JSON")
+            let (format, payload_offset) = if version.minor >= 3 {
+                if bytes.len() < 8 {
+                    return Err(CertificateError::Truncated);
+                }
+                match Serialization::from_byte(bytes[7]) {
+                    Some(f) => (f, 8),
+                    None => return Err(CertificateError::UnsupportedMode(bytes[7])),
+                }
             } else {
-                Ok(o.unwrap())
-            }
-        } else {
-            Err("Incompatible file format. File corrupted or old Edcert?")
+                (Serialization::Json, 7)
+            };
+
+            let decompressed = try!(codec.decompress_with_limit(&bytes[payload_offset..], max_bytes));
+            format.from_bytes(&decompressed)
         }
     }
 
-    /// Converts this certificate in a json-encoded byte vector.
-    pub fn encode(cert: &Certificate) -> Vec<u8> {
+    /// Decodes `compressed` with this crate's default decompressed-size budget
+    /// (`DEFAULT_MAX_DECOMPRESSED_BYTES`, 1 MiB). Dispatches on the file format version: versions
+    /// before 1.2.0 have no codec byte and are assumed to be LZMA; 1.2.0 has a codec byte but no
+    /// format byte and is assumed to be JSON; 1.3.0+ has both. This keeps files written by older
+    /// versions of this crate loadable.
+    pub fn decode(compressed: &[u8]) -> Result<Certificate, CertificateError> {
+        CertificateCompressor::decode_with_limit(compressed, DEFAULT_MAX_DECOMPRESSED_BYTES)
+    }
+
+    /// Converts this certificate in a json-encoded byte vector, LZMA-compressed at level 6.
+    /// Equivalent to `encode_full(cert, Serialization::Json, Codec::Lzma, 6)`.
+    pub fn encode(cert: &Certificate) -> Result<Vec<u8>, CertificateError> {
+        CertificateCompressor::encode_full(cert, Serialization::Json, Codec::Lzma, 6)
+    }
+
+    /// Converts this certificate into a JSON-encoded byte vector compressed with `codec`.
+    /// Equivalent to `encode_full(cert, Serialization::Json, codec, level)`.
+    pub fn encode_with(cert: &Certificate,
+                        codec: Codec,
+                        level: u32)
+                        -> Result<Vec<u8>, CertificateError> {
+        CertificateCompressor::encode_full(cert, Serialization::Json, codec, level)
+    }
+
+    /// Converts this certificate into a byte vector using `format`, compressed with `codec`,
+    /// writing both choices into dedicated header bytes so `decode` can pick the matching
+    /// deserializer and decompressor. The on-disk layout is
+    /// `magic("edc") || version(3) || codec_byte || format_byte || payload`.
+    pub fn encode_full(cert: &Certificate,
+                        format: Serialization,
+                        codec: Codec,
+                        level: u32)
+                        -> Result<Vec<u8>, CertificateError> {
+        let serialized = try!(format.to_bytes(cert));
+        let payload = try!(codec.compress(&serialized, level));
+
+        let mut out = Vec::with_capacity(3 + 3 + 1 + 1 + payload.len());
+        out.extend_from_slice(b"edc");
+        out.extend_from_slice(&CertificateCompressor::get_bytes_from_version());
+        out.push(codec.to_byte());
+        out.push(format.to_byte());
+        out.extend_from_slice(&payload);
+        Ok(out)
+    }
+
+    /// Writes `cert`'s encoded form to `w`, so callers that already have an arbitrary `Write` -
+    /// a socket, a file, an in-memory `Vec` - don't need a file path to save to. This is
+    /// deliberately scoped down from what was originally asked for ("drive the LZMA codec
+    /// incrementally"): the `lzma` crate exposes only a whole-buffer `compress`, with no
+    /// incremental/`Write`-based API to drive instead, so `encode` still builds the entire
+    /// compressed payload in memory before this writes it out in one piece. The memory-use goal
+    /// of the original request is not met; only the "no file path required" part is.
+    pub fn encode_to_writer<W: Write>(cert: &Certificate, mut w: W) -> Result<(), CertificateError> {
+        let bytes = try!(CertificateCompressor::encode(cert));
+        try!(w.write_all(&bytes));
+        Ok(())
+    }
+
+    /// Reads a certificate from `r`, so callers that already have an arbitrary `Read` don't need
+    /// a file path to load from. The header (magic/version/codec/format) is still only parsed
+    /// after the cap below, rather than incrementally off the stream. Like `encode_to_writer`,
+    /// this is scoped down from the original ask: the `lzma` crate has no incremental/`Read`-based
+    /// decompressor to drive, so the bytes still have to be fully buffered (up to
+    /// `DEFAULT_MAX_ENCODED_BYTES`, so a hostile `Read` that never ends cannot force an unbounded
+    /// buffer) before `decode` can run on them as a whole. This avoids the caller needing its own
+    /// `Vec`, but does not bound how much memory decoding itself uses - that's what
+    /// `DEFAULT_MAX_DECOMPRESSED_BYTES`/`decode_with_limit` are for.
+    pub fn decode_from_reader<R: Read>(r: R) -> Result<Certificate, CertificateError> {
+        let mut bytes = Vec::new();
+        let mut capped = r.take(DEFAULT_MAX_ENCODED_BYTES as u64 + 1);
+        try!(capped.read_to_end(&mut bytes));
+        if bytes.len() > DEFAULT_MAX_ENCODED_BYTES {
+            return Err(CertificateError::TooLarge);
+        }
+        CertificateCompressor::decode(&bytes)
+    }
+
+    /// Encodes a whole certificate chain - e.g. a leaf certificate together with the
+    /// intermediates that signed it - as one byte vector. Each certificate is JSON-encoded and
+    /// length-prefixed, and the resulting container (count + records) is LZMA-compressed as a
+    /// single unit. The on-disk layout is
+    /// `magic("edb") || version(3) || codec_byte || compress(count(4) || (len(4) || json)*)`.
+    pub fn encode_bundle(certs: &[Certificate]) -> Result<Vec<u8>, CertificateError> {
         use rustc_serialize::json;
 
-        let jsoncode = json::encode(cert).expect("Failed to encode certificate");
-        let mut compressed = lzma::compress(jsoncode.as_bytes(), 6).expect("failed to compress");
-        let magic = b"edc";
-        let version = &CertificateCompressor::get_bytes_from_version()[..];
-        edcert::copy_bytes(&mut compressed[0..6], magic, 0, 0, 3);
-        edcert::copy_bytes(&mut compressed[3..6], version, 0, 0, 3);
-        compressed
+        let mut container = Vec::new();
+        container.extend_from_slice(&u32_to_be_bytes(certs.len() as u32));
+
+        for cert in certs {
+            let jsoncode = try!(json::encode(cert).map_err(|e| CertificateError::Json(e.to_string())));
+            let bytes = jsoncode.into_bytes();
+            container.extend_from_slice(&u32_to_be_bytes(bytes.len() as u32));
+            container.extend_from_slice(&bytes);
+        }
+
+        let payload = try!(Codec::Lzma.compress(&container, 6));
+
+        let mut out = Vec::with_capacity(3 + 3 + 1 + payload.len());
+        out.extend_from_slice(CERTIFICATE_BUNDLE_MAGIC);
+        out.extend_from_slice(&CERTIFICATE_BUNDLE_FORMAT_VERSION);
+        out.push(Codec::Lzma.to_byte());
+        out.extend_from_slice(&payload);
+        Ok(out)
+    }
+
+    /// Reverses `encode_bundle`, using the crate's default decompressed-size budget
+    /// (`DEFAULT_MAX_DECOMPRESSED_BYTES`). Equivalent to
+    /// `decode_bundle_with_limit(bytes, DEFAULT_MAX_DECOMPRESSED_BYTES)`.
+    pub fn decode_bundle(bytes: &[u8]) -> Result<Vec<Certificate>, CertificateError> {
+        CertificateCompressor::decode_bundle_with_limit(bytes, DEFAULT_MAX_DECOMPRESSED_BYTES)
+    }
+
+    /// Like `decode_bundle`, but lets the caller pick the decompressed-size budget, the same way
+    /// `decode_with_limit` does for a single certificate.
+    pub fn decode_bundle_with_limit(bytes: &[u8],
+                                     max_bytes: usize)
+                                     -> Result<Vec<Certificate>, CertificateError> {
+        use rustc_serialize::json;
+        use semver::Version;
+        use semver::VersionReq;
+
+        if bytes.len() < 7 {
+            return Err(CertificateError::Truncated);
+        }
+        if &bytes[0..3] != &CERTIFICATE_BUNDLE_MAGIC[..] {
+            return Err(CertificateError::NotABundle);
+        }
+
+        let version_string = format!("{}.{}.{}", bytes[3], bytes[4], bytes[5]);
+        let version = try!(Version::parse(&version_string).map_err(|_| {
+            CertificateError::IncompatibleVersion {
+                found: version_string.clone(),
+                required: "^1.0.0".to_string(),
+            }
+        }));
+        let vreq = VersionReq::parse("^1.0.0").expect("Failed to parse version requirement.");
+        if !vreq.matches(&version) {
+            return Err(CertificateError::IncompatibleVersion {
+                found: version_string,
+                required: "^1.0.0".to_string(),
+            });
+        }
+
+        let codec = match Codec::from_byte(bytes[6]) {
+            Some(c) => c,
+            None => return Err(CertificateError::UnsupportedMode(bytes[6])),
+        };
+
+        let container = try!(codec.decompress_with_limit(&bytes[7..], max_bytes));
+
+        if container.len() < 4 {
+            return Err(CertificateError::Truncated);
+        }
+        let count = be_bytes_to_u32(&container[0..4]);
+
+        // `count` comes straight from the decompressed bytes, so it must not be trusted as a
+        // `Vec::with_capacity` hint: a malicious container a few bytes long could claim a `count`
+        // in the billions and force an allocation of gigabytes before a single record is read.
+        // Growing the vector as records are actually read keeps the allocation bounded by what
+        // `container` can actually contain, which the loop below already checks.
+        let mut certs = Vec::new();
+        let mut offset = 4;
+        for _ in 0..count {
+            if container.len() < offset + 4 {
+                return Err(CertificateError::Truncated);
+            }
+            let len = be_bytes_to_u32(&container[offset..offset + 4]) as usize;
+            offset += 4;
+
+            let end = offset + len;
+            if container.len() < end {
+                return Err(CertificateError::Truncated);
+            }
+
+            let decoded = try!(String::from_utf8(container[offset..end].to_vec())
+                .map_err(|_| CertificateError::Utf8));
+            let cert = try!(json::decode(&decoded).map_err(|e| CertificateError::Json(e.to_string())));
+            certs.push(cert);
+
+            offset = end;
+        }
+
+        Ok(certs)
+    }
+
+    /// Like `encode`, but encrypts the compressed certificate with a key derived from
+    /// `passphrase` so that the result is unreadable without it. The on-disk layout is
+    /// `magic("edc") || version(3) || mode_byte || argon2_params(12) || salt(16) || nonce(24) ||
+    /// ciphertext+tag`.
+    pub fn encode_encrypted(cert: &Certificate, passphrase: &str) -> Result<Vec<u8>, CertificateError> {
+        let plaintext = try!(CertificateCompressor::compress(cert));
+        CertificateCompressor::encrypt_payload(&plaintext, passphrase)
+    }
+
+    /// Reverses `encode_encrypted`, using the crate's default decompressed-size budget
+    /// (`DEFAULT_MAX_DECOMPRESSED_BYTES`). Returns `CertificateError::Authentication` if
+    /// `passphrase` is wrong or the file has been tampered with, since the Poly1305 tag will not
+    /// verify. Equivalent to `decode_encrypted_with_limit(bytes, passphrase,
+    /// DEFAULT_MAX_DECOMPRESSED_BYTES)`.
+    pub fn decode_encrypted(bytes: &[u8], passphrase: &str) -> Result<Certificate, CertificateError> {
+        CertificateCompressor::decode_encrypted_with_limit(bytes, passphrase, DEFAULT_MAX_DECOMPRESSED_BYTES)
+    }
+
+    /// Like `decode_encrypted`, but lets the caller pick the decompressed-size budget, the same
+    /// way `decode_with_limit` does for an unencrypted certificate.
+    pub fn decode_encrypted_with_limit(bytes: &[u8],
+                                        passphrase: &str,
+                                        max_bytes: usize)
+                                        -> Result<Certificate, CertificateError> {
+        let plaintext = try!(CertificateCompressor::decrypt_payload(bytes, passphrase));
+        CertificateCompressor::decompress_with_limit(&plaintext, max_bytes)
+    }
+
+    /// Encrypts an arbitrary payload under the same header/KDF/AEAD scheme as
+    /// `encode_encrypted`, so `CertificateLoader` can apply it to the raw private key bytes too.
+    pub(crate) fn encrypt_payload(plaintext: &[u8], passphrase: &str) -> Result<Vec<u8>, CertificateError> {
+        use chacha20poly1305::XChaCha20Poly1305;
+        use chacha20poly1305::aead::{Aead, NewAead, generic_array::GenericArray};
+        use rand::{OsRng, Rng};
+
+        let mut rng = try!(OsRng::new().map_err(|_| CertificateError::KeyDerivation));
+        let mut salt = [0u8; SALT_LEN];
+        rng.fill_bytes(&mut salt);
+        let mut nonce = [0u8; NONCE_LEN];
+        rng.fill_bytes(&mut nonce);
+
+        let key = try!(CertificateCompressor::derive_key(passphrase,
+                                                          &salt,
+                                                          ARGON2_M_COST,
+                                                          ARGON2_T_COST,
+                                                          ARGON2_PARALLELISM));
+        let cipher = XChaCha20Poly1305::new(GenericArray::from_slice(&key));
+        let ciphertext = try!(cipher.encrypt(GenericArray::from_slice(&nonce), plaintext)
+            .map_err(|_| CertificateError::Authentication));
+
+        let mut out = Vec::with_capacity(3 + 3 + 1 + ARGON2_PARAMS_LEN + SALT_LEN + NONCE_LEN +
+                                          ciphertext.len());
+        out.extend_from_slice(b"edc");
+        out.extend_from_slice(&CERTIFICATE_COMPRESSOR_ENCRYPTED_FORMAT_VERSION);
+        out.push(MODE_ARGON2ID_XCHACHA20POLY1305);
+        out.extend_from_slice(&argon2_params_to_bytes(ARGON2_M_COST, ARGON2_T_COST, ARGON2_PARALLELISM));
+        out.extend_from_slice(&salt);
+        out.extend_from_slice(&nonce);
+        out.extend_from_slice(&ciphertext);
+        Ok(out)
+    }
+
+    /// Reverses `encrypt_payload`. Files with a header minor version of 0 predate Argon2
+    /// parameters being stored at all and are decrypted with `derive_key_legacy`; 1 and above
+    /// always carry `ARGON2_PARAMS_LEN` parameter bytes right after the mode byte, which are
+    /// used instead of this crate's current `ARGON2_M_COST`/`ARGON2_T_COST`/`ARGON2_PARALLELISM`.
+    pub(crate) fn decrypt_payload(bytes: &[u8], passphrase: &str) -> Result<Vec<u8>, CertificateError> {
+        use chacha20poly1305::XChaCha20Poly1305;
+        use chacha20poly1305::aead::{Aead, NewAead, generic_array::GenericArray};
+
+        if bytes.len() < 3 + 3 + 1 {
+            return Err(CertificateError::Truncated);
+        }
+
+        let mode = bytes[6];
+        if mode != MODE_ARGON2ID_XCHACHA20POLY1305 {
+            return Err(CertificateError::UnsupportedMode(mode));
+        }
+
+        let has_stored_params = bytes[4] >= 1;
+        let params_len = if has_stored_params { ARGON2_PARAMS_LEN } else { 0 };
+        let header_len = 3 + 3 + 1 + params_len + SALT_LEN + NONCE_LEN;
+        if bytes.len() < header_len {
+            return Err(CertificateError::Truncated);
+        }
+
+        let params_start = 7;
+        let salt_start = params_start + params_len;
+        let nonce_start = salt_start + SALT_LEN;
+
+        let salt = &bytes[salt_start..nonce_start];
+        let nonce = &bytes[nonce_start..header_len];
+        let ciphertext = &bytes[header_len..];
+
+        let key = if has_stored_params {
+            let m_cost = be_bytes_to_u32(&bytes[params_start..params_start + 4]);
+            let t_cost = be_bytes_to_u32(&bytes[params_start + 4..params_start + 8]);
+            let parallelism = be_bytes_to_u32(&bytes[params_start + 8..params_start + 12]);
+            try!(CertificateCompressor::derive_key(passphrase, salt, m_cost, t_cost, parallelism))
+        } else {
+            try!(CertificateCompressor::derive_key_legacy(passphrase, salt))
+        };
+
+        let cipher = XChaCha20Poly1305::new(GenericArray::from_slice(&key));
+        cipher.decrypt(GenericArray::from_slice(nonce), ciphertext)
+            .map_err(|_| CertificateError::Authentication)
     }
 }
 
@@ -121,7 +549,7 @@ fn test_en_and_decoder() {
         "signature": null
     }"#).unwrap();
 
-    let bytes = CertificateCompressor::encode(&cert);
+    let bytes = CertificateCompressor::encode(&cert).unwrap();
     assert_eq!(&bytes[0..3], b"edc");
     assert_eq!(&bytes[3..6], CERTIFICATE_COMPRESSOR_FORMAT_VERSION);
 
@@ -145,7 +573,8 @@ fn test_decode_no_version() {
         "signature": null
     }"#).unwrap();
 
-    let mut bytes = CertificateCompressor::encode(&cert);
+    let mut bytes = CertificateCompressor::compress(&cert).unwrap();
+    bytes[0..3].copy_from_slice(b"edc");
 
     // these bytes are ASCII for "ert". They are used to simulate an older file format.
     bytes[3] = 0x65;
@@ -158,7 +587,6 @@ fn test_decode_no_version() {
 }
 
 #[test]
-#[should_panic]
 fn test_decode_old_version() {
     use rustc_serialize::json;
 
@@ -173,17 +601,20 @@ fn test_decode_old_version() {
         "signature": null
     }"#).unwrap();
 
-    let mut bytes = CertificateCompressor::encode(&cert);
+    let mut bytes = CertificateCompressor::encode(&cert).unwrap();
     bytes[3] = 0;
     bytes[4] = 1;
     bytes[5] = 0;
 
-    // this should panic, since version 0.1.0 is not semver-compatible to any current version
-    CertificateCompressor::decode(&bytes).unwrap();
+    // version 0.1.0 is not semver-compatible to any current version, so this must be a
+    // recoverable error, not a panic.
+    match CertificateCompressor::decode(&bytes) {
+        Err(CertificateError::IncompatibleVersion { .. }) => {}
+        other => panic!("expected IncompatibleVersion, got {:?}", other),
+    }
 }
 
 #[test]
-#[should_panic]
 fn test_decode_new_version() {
     use rustc_serialize::json;
 
@@ -198,7 +629,7 @@ fn test_decode_new_version() {
         "signature": null
     }"#).unwrap();
 
-    let mut bytes = CertificateCompressor::encode(&cert);
+    let mut bytes = CertificateCompressor::encode(&cert).unwrap();
 
     let mut version = CERTIFICATE_COMPRESSOR_FORMAT_VERSION;
     version[0] += 1;
@@ -207,6 +638,443 @@ fn test_decode_new_version() {
 
     edcert::copy_bytes(&mut bytes[3..6], &version, 0, 0, 3);
 
-    // this should panic, since version 0.1.0 is not semver-compatible to any current version
+    // version (major+1).0.0 is not semver-compatible to any current version, so this must be
+    // a recoverable error, not a panic.
+    match CertificateCompressor::decode(&bytes) {
+        Err(CertificateError::IncompatibleVersion { .. }) => {}
+        other => panic!("expected IncompatibleVersion, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_encrypted_en_and_decoder() {
+    use rustc_serialize::json;
+
+    let cert: Certificate = json::decode(r#"
+    {
+        "meta": {
+            "values": {}
+        },
+        "public_key": "0000000000000000000000000000000000000000000000000000000000000000",
+        "private_key": "00000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000",
+        "expires": "2020-01-01T00:00:00+00:00",
+        "signature": null
+    }"#).unwrap();
+
+    let bytes = CertificateCompressor::encode_encrypted(&cert, "correct horse battery staple")
+        .unwrap();
+    assert_eq!(&bytes[0..3], b"edc");
+    assert_eq!(&bytes[3..6], CERTIFICATE_COMPRESSOR_ENCRYPTED_FORMAT_VERSION);
+    assert_eq!(bytes[6], MODE_ARGON2ID_XCHACHA20POLY1305);
+
+    let cert2 = CertificateCompressor::decode_encrypted(&bytes, "correct horse battery staple")
+        .unwrap();
+
+    assert_eq!(cert, cert2);
+
+    // decoding without the encryption-aware path must not succeed either, since the header's
+    // major version (3) falls outside the plaintext format's `^1.0.0` requirement.
+    match CertificateCompressor::decode(&bytes) {
+        Err(CertificateError::IncompatibleVersion { .. }) => {}
+        other => panic!("expected IncompatibleVersion, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_encrypted_decode_wrong_passphrase() {
+    use rustc_serialize::json;
+
+    let cert: Certificate = json::decode(r#"
+    {
+        "meta": {
+            "values": {}
+        },
+        "public_key": "0000000000000000000000000000000000000000000000000000000000000000",
+        "private_key": "00000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000",
+        "expires": "2020-01-01T00:00:00+00:00",
+        "signature": null
+    }"#).unwrap();
+
+    let bytes = CertificateCompressor::encode_encrypted(&cert, "correct horse battery staple")
+        .unwrap();
+
+    match CertificateCompressor::decode_encrypted(&bytes, "wrong passphrase") {
+        Err(CertificateError::Authentication) => {}
+        other => panic!("expected Authentication, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_encrypted_header_stores_argon2_params() {
+    use rustc_serialize::json;
+
+    let cert: Certificate = json::decode(r#"
+    {
+        "meta": {
+            "values": {}
+        },
+        "public_key": "0000000000000000000000000000000000000000000000000000000000000000",
+        "private_key": "00000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000",
+        "expires": "2020-01-01T00:00:00+00:00",
+        "signature": null
+    }"#).unwrap();
+
+    let bytes = CertificateCompressor::encode_encrypted(&cert, "correct horse battery staple")
+        .unwrap();
+    assert_eq!(&bytes[3..6], CERTIFICATE_COMPRESSOR_ENCRYPTED_FORMAT_VERSION);
+
+    // the parameters actually used to derive the key must be readable straight out of the
+    // header, not assumed to match whatever this crate's constants currently are.
+    let params = &bytes[7..7 + ARGON2_PARAMS_LEN];
+    assert_eq!(be_bytes_to_u32(&params[0..4]), ARGON2_M_COST);
+    assert_eq!(be_bytes_to_u32(&params[4..8]), ARGON2_T_COST);
+    assert_eq!(be_bytes_to_u32(&params[8..12]), ARGON2_PARALLELISM);
+}
+
+#[test]
+fn test_decode_encrypted_accepts_legacy_header_without_stored_params() {
+    use rustc_serialize::json;
+
+    let cert: Certificate = json::decode(r#"
+    {
+        "meta": {
+            "values": {}
+        },
+        "public_key": "0000000000000000000000000000000000000000000000000000000000000000",
+        "private_key": "00000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000",
+        "expires": "2020-01-01T00:00:00+00:00",
+        "signature": null
+    }"#).unwrap();
+
+    // hand-build a 3.0.0-shaped header (no Argon2 parameter bytes) with the same KDF/AEAD the
+    // legacy derive_key_legacy path uses, to confirm files written before this fix are still
+    // decryptable.
+    use chacha20poly1305::XChaCha20Poly1305;
+    use chacha20poly1305::aead::{Aead, NewAead, generic_array::GenericArray};
+    use rand::{OsRng, Rng};
+
+    let plaintext = CertificateCompressor::compress(&cert).unwrap();
+
+    let mut rng = OsRng::new().unwrap();
+    let mut salt = [0u8; SALT_LEN];
+    rng.fill_bytes(&mut salt);
+    let mut nonce = [0u8; NONCE_LEN];
+    rng.fill_bytes(&mut nonce);
+
+    let key = CertificateCompressor::derive_key_legacy("correct horse battery staple", &salt)
+        .unwrap();
+    let cipher = XChaCha20Poly1305::new(GenericArray::from_slice(&key));
+    let ciphertext = cipher.encrypt(GenericArray::from_slice(&nonce), &plaintext[..]).unwrap();
+
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(b"edc");
+    bytes.extend_from_slice(&[3, 0, 0]);
+    bytes.push(MODE_ARGON2ID_XCHACHA20POLY1305);
+    bytes.extend_from_slice(&salt);
+    bytes.extend_from_slice(&nonce);
+    bytes.extend_from_slice(&ciphertext);
+
+    let cert2 = CertificateCompressor::decode_encrypted(&bytes, "correct horse battery staple")
+        .unwrap();
+    assert_eq!(cert, cert2);
+}
+
+#[test]
+fn test_encode_with_zstd_and_none() {
+    use rustc_serialize::json;
+
+    let cert: Certificate = json::decode(r#"
+    {
+        "meta": {
+            "values": {}
+        },
+        "public_key": "0000000000000000000000000000000000000000000000000000000000000000",
+        "private_key": "00000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000",
+        "expires": "2020-01-01T00:00:00+00:00",
+        "signature": null
+    }"#).unwrap();
+
+    for codec in &[Codec::Lzma, Codec::Zstd, Codec::None] {
+        let bytes = CertificateCompressor::encode_with(&cert, *codec, 6).unwrap();
+        assert_eq!(&bytes[0..3], b"edc");
+        assert_eq!(&bytes[3..6], CERTIFICATE_COMPRESSOR_FORMAT_VERSION);
+        assert_eq!(bytes[6], codec.to_byte());
+
+        let cert2 = CertificateCompressor::decode(&bytes).unwrap();
+        assert_eq!(cert, cert2);
+    }
+}
+
+#[test]
+fn test_decode_unknown_codec() {
+    use rustc_serialize::json;
+
+    let cert: Certificate = json::decode(r#"
+    {
+        "meta": {
+            "values": {}
+        },
+        "public_key": "0000000000000000000000000000000000000000000000000000000000000000",
+        "private_key": "00000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000",
+        "expires": "2020-01-01T00:00:00+00:00",
+        "signature": null
+    }"#).unwrap();
+
+    let mut bytes = CertificateCompressor::encode(&cert).unwrap();
+    bytes[6] = 0xff;
+
+    match CertificateCompressor::decode(&bytes) {
+        Err(CertificateError::UnsupportedMode(0xff)) => {}
+        other => panic!("expected UnsupportedMode(0xff), got {:?}", other),
+    }
+}
+
+#[test]
+fn test_encode_full_cbor_round_trip() {
+    use rustc_serialize::json;
+
+    let cert: Certificate = json::decode(r#"
+    {
+        "meta": {
+            "values": {}
+        },
+        "public_key": "0000000000000000000000000000000000000000000000000000000000000000",
+        "private_key": "00000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000",
+        "expires": "2020-01-01T00:00:00+00:00",
+        "signature": null
+    }"#).unwrap();
+
+    let bytes = CertificateCompressor::encode_full(&cert, Serialization::Cbor, Codec::Lzma, 6)
+        .unwrap();
+    assert_eq!(&bytes[0..3], b"edc");
+    assert_eq!(&bytes[3..6], CERTIFICATE_COMPRESSOR_FORMAT_VERSION);
+    assert_eq!(bytes[6], Codec::Lzma.to_byte());
+    assert_eq!(bytes[7], Serialization::Cbor.to_byte());
+
+    let cert2 = CertificateCompressor::decode(&bytes).unwrap();
+    assert_eq!(cert, cert2);
+}
+
+#[test]
+fn test_encode_full_cbor_with_zstd() {
+    use rustc_serialize::json;
+
+    let cert: Certificate = json::decode(r#"
+    {
+        "meta": {
+            "values": {}
+        },
+        "public_key": "0000000000000000000000000000000000000000000000000000000000000000",
+        "private_key": "00000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000",
+        "expires": "2020-01-01T00:00:00+00:00",
+        "signature": null
+    }"#).unwrap();
+
+    let bytes = CertificateCompressor::encode_full(&cert, Serialization::Cbor, Codec::Zstd, 6)
+        .unwrap();
+
+    let cert2 = CertificateCompressor::decode(&bytes).unwrap();
+    assert_eq!(cert, cert2);
+}
+
+#[test]
+fn test_encode_and_decode_bundle() {
+    use rustc_serialize::json;
+
+    let leaf: Certificate = json::decode(r#"
+    {
+        "meta": {
+            "values": {}
+        },
+        "public_key": "0000000000000000000000000000000000000000000000000000000000000000",
+        "private_key": "00000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000",
+        "expires": "2020-01-01T00:00:00+00:00",
+        "signature": null
+    }"#).unwrap();
+
+    let intermediate_json = r#"
+    {
+        "meta": {
+            "values": {}
+        },
+        "public_key": "1111111111111111111111111111111111111111111111111111111111111111",
+        "private_key": null,
+        "expires": "2021-01-01T00:00:00+00:00",
+        "signature": null
+    }"#;
+    let intermediate: Certificate = json::decode(intermediate_json).unwrap();
+
+    let certs = vec![leaf, intermediate];
+    let bytes = CertificateCompressor::encode_bundle(&certs).unwrap();
+    assert_eq!(&bytes[0..3], b"edb");
+
+    let certs2 = CertificateCompressor::decode_bundle(&bytes).unwrap();
+    assert_eq!(certs, certs2);
+}
+
+#[test]
+fn test_encode_to_writer_and_decode_from_reader() {
+    use rustc_serialize::json;
+
+    let cert: Certificate = json::decode(r#"
+    {
+        "meta": {
+            "values": {}
+        },
+        "public_key": "0000000000000000000000000000000000000000000000000000000000000000",
+        "private_key": "00000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000",
+        "expires": "2020-01-01T00:00:00+00:00",
+        "signature": null
+    }"#).unwrap();
+
+    let mut buffer = Vec::new();
+    CertificateCompressor::encode_to_writer(&cert, &mut buffer).unwrap();
+    assert_eq!(&buffer[0..3], b"edc");
+
+    let cert2 = CertificateCompressor::decode_from_reader(&buffer[..]).unwrap();
+    assert_eq!(cert, cert2);
+}
+
+#[test]
+fn test_decode_from_reader_rejects_oversized_stream() {
+    use std::io::Read;
+
+    // a `Read` that never reaches EOF on its own, like a hostile socket. decode_from_reader must
+    // stop buffering it once DEFAULT_MAX_ENCODED_BYTES is exceeded instead of reading to_end
+    // unconditionally.
+    let reader = std::io::repeat(0u8).take(DEFAULT_MAX_ENCODED_BYTES as u64 + 1);
+    match CertificateCompressor::decode_from_reader(reader) {
+        Err(CertificateError::TooLarge) => {}
+        other => panic!("expected TooLarge, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_decode_rejects_truncated_input() {
+    match CertificateCompressor::decode(b"edc") {
+        Err(CertificateError::Truncated) => {}
+        other => panic!("expected Truncated, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_decode_with_limit_rejects_oversized_payload() {
+    use rustc_serialize::json;
+
+    let cert: Certificate = json::decode(r#"
+    {
+        "meta": {
+            "values": {}
+        },
+        "public_key": "0000000000000000000000000000000000000000000000000000000000000000",
+        "private_key": "00000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000",
+        "expires": "2020-01-01T00:00:00+00:00",
+        "signature": null
+    }"#).unwrap();
+
+    let bytes = CertificateCompressor::encode(&cert).unwrap();
+
+    match CertificateCompressor::decode_with_limit(&bytes, 1) {
+        Err(CertificateError::TooLarge) => {}
+        other => panic!("expected TooLarge, got {:?}", other),
+    }
+
+    // the default budget is generous enough for a real certificate.
     CertificateCompressor::decode(&bytes).unwrap();
 }
+
+#[test]
+fn test_decode_bundle_rejects_single_certificate_file() {
+    use rustc_serialize::json;
+
+    let cert: Certificate = json::decode(r#"
+    {
+        "meta": {
+            "values": {}
+        },
+        "public_key": "0000000000000000000000000000000000000000000000000000000000000000",
+        "private_key": "00000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000",
+        "expires": "2020-01-01T00:00:00+00:00",
+        "signature": null
+    }"#).unwrap();
+
+    let bytes = CertificateCompressor::encode(&cert).unwrap();
+
+    match CertificateCompressor::decode_bundle(&bytes) {
+        Err(CertificateError::NotABundle) => {}
+        other => panic!("expected NotABundle, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_decode_bundle_rejects_bogus_count_without_preallocating() {
+    // a container that claims ~4 billion records but carries none of them. decode_bundle must
+    // validate before trusting `count` as a `Vec::with_capacity` hint, or this would attempt a
+    // multi-gigabyte allocation.
+    let container = vec![0xff, 0xff, 0xff, 0xff];
+    let payload = Codec::Lzma.compress(&container, 6).unwrap();
+
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(CERTIFICATE_BUNDLE_MAGIC);
+    bytes.extend_from_slice(&CERTIFICATE_BUNDLE_FORMAT_VERSION);
+    bytes.push(Codec::Lzma.to_byte());
+    bytes.extend_from_slice(&payload);
+
+    match CertificateCompressor::decode_bundle(&bytes) {
+        Err(CertificateError::Truncated) => {}
+        other => panic!("expected Truncated, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_decode_bundle_with_limit_rejects_oversized_payload() {
+    use rustc_serialize::json;
+
+    let leaf: Certificate = json::decode(r#"
+    {
+        "meta": {
+            "values": {}
+        },
+        "public_key": "0000000000000000000000000000000000000000000000000000000000000000",
+        "private_key": "00000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000",
+        "expires": "2020-01-01T00:00:00+00:00",
+        "signature": null
+    }"#).unwrap();
+
+    let bytes = CertificateCompressor::encode_bundle(&[leaf]).unwrap();
+
+    match CertificateCompressor::decode_bundle_with_limit(&bytes, 1) {
+        Err(CertificateError::TooLarge) => {}
+        other => panic!("expected TooLarge, got {:?}", other),
+    }
+
+    // the default budget is generous enough for a real bundle.
+    CertificateCompressor::decode_bundle(&bytes).unwrap();
+}
+
+#[test]
+fn test_decode_encrypted_with_limit_rejects_oversized_payload() {
+    use rustc_serialize::json;
+
+    let cert: Certificate = json::decode(r#"
+    {
+        "meta": {
+            "values": {}
+        },
+        "public_key": "0000000000000000000000000000000000000000000000000000000000000000",
+        "private_key": "00000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000000",
+        "expires": "2020-01-01T00:00:00+00:00",
+        "signature": null
+    }"#).unwrap();
+
+    let bytes = CertificateCompressor::encode_encrypted(&cert, "correct horse battery staple")
+        .unwrap();
+
+    match CertificateCompressor::decode_encrypted_with_limit(&bytes, "correct horse battery staple", 1) {
+        Err(CertificateError::TooLarge) => {}
+        other => panic!("expected TooLarge, got {:?}", other),
+    }
+
+    // the default budget is generous enough for a real certificate.
+    CertificateCompressor::decode_encrypted(&bytes, "correct horse battery staple").unwrap();
+}
@@ -21,7 +21,10 @@
 // SOFTWARE.
 
 use edcert::certificate::Certificate;
+use certificate_bundle::CertificateBundle;
 use certificate_compressor::CertificateCompressor;
+use error::CertificateError;
+use std::io::{Read, Write};
 
 /// This type can be used to load `Certificate`s.
 pub struct CertificateLoader;
@@ -29,12 +32,12 @@ pub struct CertificateLoader;
 impl CertificateLoader {
     /// Saves this certificate into a folder: one file for the certificate and one file for the
     /// private key.
-    pub fn save_to_folder(cert: &Certificate, folder: &str) -> Result<(), &'static str> {
+    pub fn save_to_folder(cert: &Certificate, folder: &str) -> Result<(), CertificateError> {
         use std::fs::DirBuilder;
         use std::fs::metadata;
 
-        if metadata(&folder).is_err() && DirBuilder::new().create(&folder).is_err() {
-            return Err("Failed to create folder");
+        if metadata(&folder).is_err() {
+            try!(DirBuilder::new().create(&folder));
         }
 
         if cert.has_private_key() {
@@ -47,7 +50,7 @@ impl CertificateLoader {
     }
 
     /// Reads a certificate from a folder like it has been saved with save_to_folder
-    pub fn load_from_folder(folder: &str) -> Result<Certificate, &'static str> {
+    pub fn load_from_folder(folder: &str) -> Result<Certificate, CertificateError> {
 
         let mut cert = try!(CertificateLoader::load_from_file(&format!("{}/certificate.edc",
                                                                        &folder)));
@@ -56,80 +59,142 @@ impl CertificateLoader {
 
     }
 
-    /// Saves the certificate in encoded form to a file
-    pub fn save_to_file(cert: &Certificate, filename: &str) -> Result<(), &'static str> {
+    /// Like `save_to_folder`, but encrypts both `certificate.edc` and `private.key` with a key
+    /// derived from `passphrase`, so neither file is readable by anyone with filesystem access
+    /// but no passphrase.
+    pub fn save_to_folder_encrypted(cert: &Certificate,
+                                     folder: &str,
+                                     passphrase: &str)
+                                     -> Result<(), CertificateError> {
+        use std::fs::DirBuilder;
         use std::fs::File;
+        use std::fs::metadata;
         use std::io::Write;
 
-        let mut certificate_file: File = match File::create(filename) {
-            Ok(x) => x,
-            Err(_) => return Err("Failed to create certificate file"),
-        };
+        if metadata(&folder).is_err() {
+            try!(DirBuilder::new().create(&folder));
+        }
 
-        let compressed = CertificateCompressor::encode(cert);
-        match certificate_file.write(&*compressed) {
-            Ok(_) => Ok(()),
-            Err(_) => Err("Failed to write certificate to File."),
+        if cert.has_private_key() {
+            let bytes: &[u8] = match cert.private_key() {
+                Some(x) => x,
+                None => return Err(CertificateError::NoPrivateKey),
+            };
+            let encrypted = try!(CertificateCompressor::encrypt_payload(bytes, passphrase));
+            let mut private_keyfile = try!(File::create(&format!("{}/private.key", &folder)));
+            try!(private_keyfile.write_all(&encrypted));
         }
+
+        let compressed = try!(CertificateCompressor::encode_encrypted(cert, passphrase));
+        let mut certificate_file = try!(File::create(&format!("{}/certificate.edc", &folder)));
+        try!(certificate_file.write(&*compressed));
+
+        Ok(())
+    }
+
+    /// Reverses `save_to_folder_encrypted`.
+    pub fn load_from_folder_encrypted(folder: &str, passphrase: &str) -> Result<Certificate, CertificateError> {
+        use std::fs::File;
+        use std::io::Read;
+
+        let mut certificate_file = try!(File::open(format!("{}/certificate.edc", &folder)));
+        let mut compressed = Vec::new();
+        try!(certificate_file.read_to_end(&mut compressed));
+        let mut cert = try!(CertificateCompressor::decode_encrypted(&compressed, passphrase));
+
+        let mut private_key_file = try!(File::open(format!("{}/private.key", &folder)));
+        let mut encrypted = Vec::new();
+        try!(private_key_file.read_to_end(&mut encrypted));
+        let private_key = try!(CertificateCompressor::decrypt_payload(&encrypted, passphrase));
+        cert.set_private_key(private_key);
+
+        Ok(cert)
+    }
+
+    /// Saves the certificate in encoded form to a file
+    pub fn save_to_file(cert: &Certificate, filename: &str) -> Result<(), CertificateError> {
+        use std::fs::File;
+
+        let certificate_file: File = try!(File::create(filename));
+        CertificateLoader::save_to_writer(cert, certificate_file)
+    }
+
+    /// Like `save_to_file`, but writes to any `Write` instead of a file path, so callers can
+    /// stream a certificate straight to a socket or an in-memory `Vec`.
+    pub fn save_to_writer<W: Write>(cert: &Certificate, w: W) -> Result<(), CertificateError> {
+        CertificateCompressor::encode_to_writer(cert, w)
     }
 
     /// Saves the private key to a file. Just the binary string.
-    pub fn save_private_key(cert: &Certificate, filename: &str) -> Result<(), &'static str> {
+    pub fn save_private_key(cert: &Certificate, filename: &str) -> Result<(), CertificateError> {
         use std::fs::File;
         use std::io::Write;
 
         let bytes: &[u8] = match cert.private_key() {
             Some(x) => x,
-            None => return Err("The certificate has no private key."),
+            None => return Err(CertificateError::NoPrivateKey),
         };
 
-        let mut private_keyfile: File = match File::create(&filename) {
-            Ok(x) => x,
-            Err(_) => return Err("Failed to create private key file."),
-        };
+        let mut private_keyfile: File = try!(File::create(&filename));
 
-        match private_keyfile.write_all(bytes) {
-            Ok(_) => Ok(()),
-            Err(_) => Err("Failed to write private key file."),
-        }
+        try!(private_keyfile.write_all(bytes));
+        Ok(())
     }
 
     /// This method loads a certificate from a file.
-    pub fn load_from_file(filename: &str) -> Result<Certificate, &'static str> {
+    pub fn load_from_file(filename: &str) -> Result<Certificate, CertificateError> {
         use std::fs::File;
-        use std::io::Read;
 
         let filename: String = filename.to_string();
-        let mut certificate_file: File = match File::open(filename) {
-            Err(_) => return Err("Failed to open certificate file."),
-            Ok(x) => x,
-        };
-        let mut compressed = Vec::new();
-        if let Err(_) = certificate_file.read_to_end(&mut compressed) {
-            return Err("Failed to read certificate");
-        }
-        CertificateCompressor::decode(&*compressed)
+        let certificate_file: File = try!(File::open(filename));
+        CertificateLoader::load_from_reader(certificate_file)
+    }
+
+    /// Like `load_from_file`, but reads from any `Read` instead of a file path, so callers can
+    /// stream a certificate from a socket or an in-memory buffer.
+    pub fn load_from_reader<R: Read>(r: R) -> Result<Certificate, CertificateError> {
+        CertificateCompressor::decode_from_reader(r)
     }
 
     /// This method reads a private key from a file and sets it in this certificate.
-    pub fn load_private_key(cert: &mut Certificate, filename: &str) -> Result<(), &'static str> {
+    pub fn load_private_key(cert: &mut Certificate, filename: &str) -> Result<(), CertificateError> {
         use std::fs::File;
         use std::io::Read;
 
         let filename: String = filename.to_string();
-        let mut private_key_file: File = match File::open(filename) {
-            Err(_) => return Err("Failed to open private kye file."),
-            Ok(x) => x,
-        };
+        let mut private_key_file: File = try!(File::open(filename));
         let mut private_key = Vec::new();
-        if let Err(_) = private_key_file.read_to_end(&mut private_key) {
-            return Err("Failed to read private key");
-        }
+        try!(private_key_file.read_to_end(&mut private_key));
 
         cert.set_private_key(private_key);
 
         Ok(())
     }
+
+    /// Saves a whole certificate chain - e.g. a leaf certificate together with the intermediate
+    /// certificates that signed it - to a single file.
+    pub fn save_bundle_to_file(bundle: &CertificateBundle, filename: &str) -> Result<(), CertificateError> {
+        use std::fs::File;
+        use std::io::Write;
+
+        let mut bundle_file: File = try!(File::create(filename));
+        let compressed = try!(CertificateCompressor::encode_bundle(&bundle.certificates));
+        try!(bundle_file.write(&*compressed));
+        Ok(())
+    }
+
+    /// Reverses `save_bundle_to_file`.
+    pub fn load_bundle_from_file(filename: &str) -> Result<CertificateBundle, CertificateError> {
+        use std::fs::File;
+        use std::io::Read;
+
+        let filename: String = filename.to_string();
+        let mut bundle_file: File = try!(File::open(filename));
+        let mut compressed = Vec::new();
+        try!(bundle_file.read_to_end(&mut compressed));
+        let certificates = try!(CertificateCompressor::decode_bundle(&*compressed));
+        Ok(CertificateBundle::new(certificates))
+    }
 }
 
 #[test]
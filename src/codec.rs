@@ -0,0 +1,226 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2016 Marvin Böcker
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use error::CertificateError;
+use lzma;
+use std::io::Read;
+use zstd;
+
+/// The compression algorithm a certificate's payload was stored with. This is written as a
+/// single header byte so `CertificateCompressor::decode` can dispatch to the right decompressor
+/// without guessing from magic bytes embedded in the payload itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Codec {
+    /// LZMA via the `lzma` crate. The original and still the default codec.
+    Lzma,
+    /// Zstandard. Comparable compression ratios to LZMA but much faster to decompress, which
+    /// matters when a service loads many certificates at startup.
+    Zstd,
+    /// No compression at all.
+    None,
+}
+
+impl Codec {
+    /// The header byte this codec is written as.
+    pub(crate) fn to_byte(self) -> u8 {
+        match self {
+            Codec::Lzma => 0,
+            Codec::Zstd => 1,
+            Codec::None => 2,
+        }
+    }
+
+    /// Looks up the codec a header byte refers to, or `None` if it names a codec this version
+    /// of the crate does not implement.
+    pub(crate) fn from_byte(byte: u8) -> Option<Codec> {
+        match byte {
+            0 => Some(Codec::Lzma),
+            1 => Some(Codec::Zstd),
+            2 => Some(Codec::None),
+            _ => None,
+        }
+    }
+
+    /// Compresses `data` with this codec. `level` is only meaningful for `Lzma` and `Zstd`.
+    pub(crate) fn compress(self, data: &[u8], level: u32) -> Result<Vec<u8>, CertificateError> {
+        match self {
+            Codec::Lzma => lzma::compress(data, level).map_err(|_| CertificateError::Decompress),
+            Codec::Zstd => {
+                zstd::encode_all(data, level as i32).map_err(|_| CertificateError::Decompress)
+            }
+            Codec::None => Ok(data.to_vec()),
+        }
+    }
+
+    /// Reverses `compress`, bounded by `max_bytes`: returns `CertificateError::TooLarge` instead
+    /// of producing more than `max_bytes`.
+    ///
+    /// `Zstd` streams its output through a reader capped at `max_bytes`, so a crafted payload
+    /// never forces an allocation bigger than the budget. `None` only ever needs to inspect
+    /// `data.len()`. `Lzma` is harder: the `lzma` crate exposes only a whole-buffer `decompress`
+    /// (see `CertificateCompressor::compress`), so there is no way to stop it mid-decompression.
+    /// Instead, `data`'s own XZ Stream Footer and Index are parsed (`xz_declared_size`) to read
+    /// the uncompressed size the encoder itself recorded there, and decompression is refused
+    /// outright, before `lzma::decompress` ever runs, if that size is missing or exceeds
+    /// `max_bytes`. The `decompressed.len() > max_bytes` check afterwards is defense in depth,
+    /// not the primary guard: a well-formed XZ stream's Index is required to match what its
+    /// blocks actually decompress to, but nothing here verifies that a crafted file upholds that
+    /// requirement before the underlying decompressor runs.
+    pub(crate) fn decompress_with_limit(self,
+                                         data: &[u8],
+                                         max_bytes: usize)
+                                         -> Result<Vec<u8>, CertificateError> {
+        match self {
+            Codec::Lzma => {
+                match xz_declared_size(data) {
+                    Some(declared) if declared <= max_bytes as u64 => {}
+                    _ => return Err(CertificateError::TooLarge),
+                }
+                let decompressed = try!(lzma::decompress(data).map_err(|_| CertificateError::Decompress));
+                if decompressed.len() > max_bytes {
+                    return Err(CertificateError::TooLarge);
+                }
+                Ok(decompressed)
+            }
+            Codec::Zstd => {
+                let decoder = try!(zstd::Decoder::new(data).map_err(|_| CertificateError::Decompress));
+                let mut capped = decoder.take(max_bytes as u64 + 1);
+                let mut out = Vec::new();
+                try!(capped.read_to_end(&mut out).map_err(|_| CertificateError::Decompress));
+                if out.len() > max_bytes {
+                    return Err(CertificateError::TooLarge);
+                }
+                Ok(out)
+            }
+            Codec::None => {
+                if data.len() > max_bytes {
+                    return Err(CertificateError::TooLarge);
+                }
+                Ok(data.to_vec())
+            }
+        }
+    }
+}
+
+/// Reads the uncompressed size an XZ stream's own Index declares, by parsing the Stream Footer
+/// (the last 12 bytes: CRC32, Backward Size, Stream Flags, magic `"YZ"`) to locate the Index and
+/// summing the uncompressed size of each of its records. Returns `None` if `data` is not a
+/// well-formed single-stream XZ container this can make sense of, which `decompress_with_limit`
+/// treats the same as a declared size that exceeds the caller's budget.
+fn xz_declared_size(data: &[u8]) -> Option<u64> {
+    if data.len() < 12 {
+        return None;
+    }
+
+    let footer = &data[data.len() - 12..];
+    if footer[10] != b'Y' || footer[11] != b'Z' {
+        return None;
+    }
+
+    let backward_size = u32::from(footer[4]) | (u32::from(footer[5]) << 8) |
+                         (u32::from(footer[6]) << 16) |
+                         (u32::from(footer[7]) << 24);
+    let index_len = (u64::from(backward_size) + 1) * 4;
+    let payload_len = (data.len() - 12) as u64;
+    if index_len < 2 || index_len > payload_len {
+        return None;
+    }
+
+    let index_start = data.len() - 12 - index_len as usize;
+    let index = &data[index_start..data.len() - 12];
+    if index[0] != 0 {
+        // Index Indicator must be 0x00; anything else means this isn't an Index at all.
+        return None;
+    }
+
+    let mut pos = 1;
+    let record_count = match read_vli(index, &mut pos) {
+        Some(n) => n,
+        None => return None,
+    };
+
+    let mut total: u64 = 0;
+    for _ in 0..record_count {
+        // Unpadded Size, not needed here, but must still be consumed to reach the next record.
+        if read_vli(index, &mut pos).is_none() {
+            return None;
+        }
+        let uncompressed_size = match read_vli(index, &mut pos) {
+            Some(n) => n,
+            None => return None,
+        };
+        total = match total.checked_add(uncompressed_size) {
+            Some(n) => n,
+            None => return None,
+        };
+    }
+
+    Some(total)
+}
+
+/// Reads one XZ variable-length integer starting at `*pos` (base-128, least-significant group
+/// first, continuation bit set on every group but the last), advancing `*pos` past it.
+fn read_vli(bytes: &[u8], pos: &mut usize) -> Option<u64> {
+    let mut result: u64 = 0;
+    for i in 0..9 {
+        let byte = match bytes.get(*pos) {
+            Some(b) => *b,
+            None => return None,
+        };
+        *pos += 1;
+        result |= u64::from(byte & 0x7f) << (i * 7);
+        if byte & 0x80 == 0 {
+            return Some(result);
+        }
+    }
+    None
+}
+
+#[test]
+fn test_decompress_with_limit_rejects_lzma_bomb_by_declared_size() {
+    // a real payload that compresses small but whose XZ Index honestly declares a huge
+    // uncompressed size - the shape a real LZMA bomb takes, as opposed to the toy case of a
+    // ratio check (shrinking `data.len()` itself) the old pre-check could only catch.
+    let mut repetitive = Vec::new();
+    for _ in 0..1_000_000 {
+        repetitive.push(b'a');
+    }
+    let compressed = Codec::Lzma.compress(&repetitive, 9).unwrap();
+    assert!(compressed.len() < repetitive.len() / 100);
+
+    match Codec::Lzma.decompress_with_limit(&compressed, 1024) {
+        Err(CertificateError::TooLarge) => {}
+        other => panic!("expected TooLarge, got {:?}", other),
+    }
+
+    // ... and it's still readable under a budget that actually fits it.
+    let out = Codec::Lzma.decompress_with_limit(&compressed, repetitive.len()).unwrap();
+    assert_eq!(out, repetitive);
+}
+
+#[test]
+fn test_decompress_with_limit_rejects_lzma_without_parseable_footer() {
+    match Codec::Lzma.decompress_with_limit(b"not an xz stream", 1024) {
+        Err(CertificateError::TooLarge) => {}
+        other => panic!("expected TooLarge, got {:?}", other),
+    }
+}
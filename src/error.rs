@@ -0,0 +1,124 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2016 Marvin Böcker
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+/// Everything that can go wrong while encoding, decoding, saving or loading a certificate.
+#[derive(Debug)]
+pub enum CertificateError {
+    /// Reading or writing the underlying file failed.
+    Io(io::Error),
+    /// The payload could not be compressed or decompressed.
+    Decompress,
+    /// The decompressed bytes were not valid UTF-8.
+    Utf8,
+    /// The certificate could not be encoded to or decoded from JSON or CBOR.
+    Json(String),
+    /// The file format version found in the header is not supported by this version of the crate.
+    IncompatibleVersion {
+        /// The version found in the file's header.
+        found: String,
+        /// The version requirement this crate supports.
+        required: String,
+    },
+    /// The data ends before a complete header or, in a bundle, before a complete record.
+    Truncated,
+    /// The certificate has no private key to save.
+    NoPrivateKey,
+    /// Deriving a key from the passphrase failed.
+    KeyDerivation,
+    /// The ciphertext's authentication tag did not match: wrong passphrase or tampered data.
+    Authentication,
+    /// The header names an encryption mode, codec or serialization format this version of the
+    /// crate does not implement.
+    UnsupportedMode(u8),
+    /// The bytes do not start with the bundle magic, so they cannot be a `CertificateBundle`.
+    NotABundle,
+    /// The encoded input, or the payload decompressed from it, exceeds the configured maximum
+    /// number of bytes. Returned instead of letting a crafted or corrupted input exhaust memory.
+    TooLarge,
+}
+
+impl fmt::Display for CertificateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CertificateError::Io(ref e) => write!(f, "I/O error: {}", e),
+            CertificateError::Decompress => write!(f, "failed to compress or decompress certificate"),
+            CertificateError::Utf8 => write!(f, "failed to read UTF8 from decompressed vector"),
+            CertificateError::Json(ref e) => write!(f, "failed to process certificate JSON: {}", e),
+            CertificateError::IncompatibleVersion { ref found, ref required } => {
+                write!(f,
+                       "incompatible file format version {} (requires {}). File corrupted or old \
+                        Edcert?",
+                       found,
+                       required)
+            }
+            CertificateError::Truncated => write!(f, "certificate data ends before a complete header or record"),
+            CertificateError::NoPrivateKey => write!(f, "the certificate has no private key"),
+            CertificateError::KeyDerivation => write!(f, "failed to derive a key from the passphrase"),
+            CertificateError::Authentication => {
+                write!(f, "failed to authenticate ciphertext: wrong passphrase or corrupted file")
+            }
+            CertificateError::UnsupportedMode(mode) => {
+                write!(f, "unsupported encryption mode, codec or format byte {}", mode)
+            }
+            CertificateError::NotABundle => write!(f, "data does not start with the certificate bundle magic"),
+            CertificateError::TooLarge => {
+                write!(f, "input or decompressed payload exceeds the configured maximum size")
+            }
+        }
+    }
+}
+
+impl Error for CertificateError {
+    fn description(&self) -> &str {
+        match *self {
+            CertificateError::Io(_) => "I/O error",
+            CertificateError::Decompress => "failed to compress or decompress certificate",
+            CertificateError::Utf8 => "invalid UTF8 in decompressed certificate",
+            CertificateError::Json(_) => "failed to process certificate JSON",
+            CertificateError::IncompatibleVersion { .. } => "incompatible file format version",
+            CertificateError::Truncated => "truncated certificate data",
+            CertificateError::NoPrivateKey => "certificate has no private key",
+            CertificateError::KeyDerivation => "key derivation failed",
+            CertificateError::Authentication => "ciphertext authentication failed",
+            CertificateError::UnsupportedMode(_) => "unsupported encryption mode, codec or format",
+            CertificateError::NotABundle => "not a certificate bundle",
+            CertificateError::TooLarge => "input or decompressed payload too large",
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            CertificateError::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for CertificateError {
+    fn from(e: io::Error) -> CertificateError {
+        CertificateError::Io(e)
+    }
+}
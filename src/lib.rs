@@ -23,18 +23,39 @@
 //! This crate defines two structs: A `CertificateCompressor` which JSON-encodes a given
 //! certificate and then uses LZMA to compress it. It should have similar size to a custom
 //! binary encoder. The other defined struct is a `CertificateLoader`, which does the exact
-//! opposite.
+//! opposite. `CertificateCompressor` also offers an opt-in encrypted variant so that saved
+//! certificates are not readable by anyone with filesystem access to them.
 
 #![deny(missing_docs)]
 
+extern crate argon2;
+extern crate chacha20poly1305;
 extern crate chrono;
+extern crate ciborium;
 extern crate lzma;
+extern crate rand;
 extern crate rustc_serialize;
 extern crate edcert;
 extern crate semver;
+extern crate zstd;
+
+/// This module contains the error type returned by the other modules.
+pub mod error;
+
+/// This module contains the `Codec` enum selecting the compression backend a certificate is
+/// stored with.
+pub mod codec;
+
+/// This module contains the `Serialization` enum selecting how a certificate is encoded before
+/// compression.
+pub mod serialization;
 
 /// This module contains the logic to save a certificate.
 pub mod certificate_compressor;
 
 /// This module contaisn the logic to load a certificate.
 pub mod certificate_loader;
+
+/// This module contains the `CertificateBundle` type, a chain of certificates saved and loaded
+/// as a single unit.
+pub mod certificate_bundle;
@@ -0,0 +1,176 @@
+// The MIT License (MIT)
+//
+// Copyright (c) 2016 Marvin Böcker
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in all
+// copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+use ciborium::value::Value as CborValue;
+use edcert::certificate::Certificate;
+use error::CertificateError;
+use rustc_serialize::json::Json;
+use rustc_serialize::json;
+use std::collections::BTreeMap;
+
+/// How a `Certificate` is turned into bytes before compression. `Certificate` only implements
+/// `rustc_serialize`, not `serde` or `ciborium`'s own traits, so `Cbor` cannot serialize it
+/// directly: it round-trips through `Certificate`'s existing JSON encoding and re-shapes that
+/// generic tree into CBOR instead. That means `Cbor` does strictly more work than `Json` (a full
+/// JSON encode/decode plus a CBOR conversion on top of it) — it exists for the smaller encoded
+/// size, not for speed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Serialization {
+    /// The original encoding: `rustc_serialize`'s JSON.
+    Json,
+    /// CBOR. Smaller than JSON only through CBOR's more compact framing: the key and signature
+    /// fields stay hex-encoded text, exactly as long as in the JSON encoding. See
+    /// `Serialization`'s doc comment for why this is not the faster path.
+    Cbor,
+}
+
+impl Serialization {
+    /// The header byte this serialization is written as.
+    pub(crate) fn to_byte(self) -> u8 {
+        match self {
+            Serialization::Json => 0,
+            Serialization::Cbor => 1,
+        }
+    }
+
+    /// Looks up the serialization a header byte refers to, or `None` if it names one this
+    /// version of the crate does not implement.
+    pub(crate) fn from_byte(byte: u8) -> Option<Serialization> {
+        match byte {
+            0 => Some(Serialization::Json),
+            1 => Some(Serialization::Cbor),
+            _ => None,
+        }
+    }
+
+    /// Serializes `cert` with this format.
+    pub(crate) fn to_bytes(self, cert: &Certificate) -> Result<Vec<u8>, CertificateError> {
+        let jsoncode = try!(json::encode(cert).map_err(|e| CertificateError::Json(e.to_string())));
+
+        match self {
+            Serialization::Json => Ok(jsoncode.into_bytes()),
+            Serialization::Cbor => {
+                let value = try!(Json::from_str(&jsoncode)
+                    .map_err(|e| CertificateError::Json(e.to_string())));
+
+                let mut out = Vec::new();
+                try!(::ciborium::ser::into_writer(&json_to_cbor(&value), &mut out)
+                    .map_err(|e| CertificateError::Json(e.to_string())));
+                Ok(out)
+            }
+        }
+    }
+
+    /// Reverses `to_bytes`.
+    pub(crate) fn from_bytes(self, bytes: &[u8]) -> Result<Certificate, CertificateError> {
+        match self {
+            Serialization::Json => {
+                let decoded = try!(String::from_utf8(bytes.to_vec())
+                    .map_err(|_| CertificateError::Utf8));
+                json::decode(&decoded).map_err(|e| CertificateError::Json(e.to_string()))
+            }
+            Serialization::Cbor => {
+                let value: CborValue = try!(::ciborium::de::from_reader(bytes)
+                    .map_err(|e| CertificateError::Json(e.to_string())));
+
+                let jsoncode = try!(cbor_to_json(&value)).to_string();
+                json::decode(&jsoncode).map_err(|e| CertificateError::Json(e.to_string()))
+            }
+        }
+    }
+}
+
+fn json_to_cbor(value: &Json) -> CborValue {
+    match *value {
+        Json::I64(n) => CborValue::Integer(n.into()),
+        Json::U64(n) => CborValue::Integer(n.into()),
+        Json::F64(n) => CborValue::Float(n),
+        Json::String(ref s) => CborValue::Text(s.clone()),
+        Json::Boolean(b) => CborValue::Bool(b),
+        Json::Array(ref arr) => CborValue::Array(arr.iter().map(json_to_cbor).collect()),
+        Json::Object(ref obj) => {
+            CborValue::Map(obj.iter()
+                .map(|(k, v)| (CborValue::Text(k.clone()), json_to_cbor(v)))
+                .collect())
+        }
+        Json::Null => CborValue::Null,
+    }
+}
+
+/// Reverses `json_to_cbor`. Fallible because a `CborValue::Integer` covers CBOR's full integer
+/// range, from -2^64 to 2^64 - 1, while `Json` can only hold what fits in an `i64` or a `u64`; an
+/// integer outside both ranges is rejected with `CertificateError::Json` instead of silently
+/// truncating, which is what casting straight to `i64` used to do.
+fn cbor_to_json(value: &CborValue) -> Result<Json, CertificateError> {
+    match *value {
+        CborValue::Integer(n) => {
+            let n = i128::from(n);
+            if n >= i64::min_value() as i128 && n <= i64::max_value() as i128 {
+                Ok(Json::I64(n as i64))
+            } else if n >= 0 && n <= u64::max_value() as i128 {
+                Ok(Json::U64(n as u64))
+            } else {
+                Err(CertificateError::Json(format!("CBOR integer {} does not fit in a certificate's JSON representation", n)))
+            }
+        }
+        CborValue::Float(n) => Ok(Json::F64(n)),
+        CborValue::Text(ref s) => Ok(Json::String(s.clone())),
+        CborValue::Bool(b) => Ok(Json::Boolean(b)),
+        CborValue::Array(ref arr) => {
+            let mut items = Vec::with_capacity(arr.len());
+            for item in arr {
+                items.push(try!(cbor_to_json(item)));
+            }
+            Ok(Json::Array(items))
+        }
+        CborValue::Map(ref map) => {
+            let mut obj = BTreeMap::new();
+            for &(ref k, ref v) in map {
+                if let CborValue::Text(ref key) = *k {
+                    obj.insert(key.clone(), try!(cbor_to_json(v)));
+                }
+            }
+            Ok(Json::Object(obj))
+        }
+        _ => Ok(Json::Null),
+    }
+}
+
+#[test]
+fn test_cbor_round_trip_preserves_large_u64() {
+    let original = Json::U64(u64::max_value());
+    let cbor = json_to_cbor(&original);
+    let roundtripped = cbor_to_json(&cbor).expect("u64::max_value() should round-trip");
+    assert_eq!(original, roundtripped);
+}
+
+#[test]
+fn test_cbor_to_json_rejects_integer_out_of_range() {
+    // -2^64, the most negative value CBOR's `Integer` can hold, but neither of `Json`'s
+    // `I64`/`U64` variants. The old `i128::from(n) as i64` cast used to silently truncate this
+    // instead of reporting the loss.
+    let out_of_range = CborValue::Integer(::std::convert::TryFrom::try_from(-(1i128 << 64)).unwrap());
+    match cbor_to_json(&out_of_range) {
+        Err(CertificateError::Json(_)) => {}
+        other => panic!("expected a Json error, got {:?}", other),
+    }
+}